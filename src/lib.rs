@@ -3,24 +3,246 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
-    cell::{RefCell, RefMut},
-    collections::VecDeque,
+    cell::{Cell, RefCell, RefMut},
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt::{self, Display, Formatter},
+    future::{Future, poll_fn},
     ops::{Deref, DerefMut},
-    rc::Rc,
+    pin::Pin,
+    rc::{Rc, Weak},
+    task::{Context, Poll, Waker},
+    time::Duration,
 };
 
+use futures_core::Stream;
 use wasm_bindgen::prelude::*;
 use web_sys::{
-    BinaryType, CloseEvent, Event, MessageEvent, WebSocket,
-    js_sys::{ArrayBuffer, JsString, Uint8Array},
+    BinaryType, CloseEvent, DomException, Event, MessageEvent, Performance, WebSocket,
+    js_sys::{self, Array, ArrayBuffer, Function, JsString, Math, Uint8Array},
 };
 
 pub type Handler<T> = Option<Box<dyn FnMut(T)>>;
+pub type ClosePredicate = Option<Box<dyn FnMut(&CloseReason) -> bool>>;
+pub type RawDataHandler = Option<Box<dyn FnMut(&JsValue)>>;
+pub type MessagePredicate = Option<Box<dyn FnMut(&Message) -> bool>>;
+pub type ResumeTokenExtractor = Option<Box<dyn FnMut(&Message) -> Option<String>>>;
+pub type ResumeMessage = Option<Box<dyn FnMut(&str) -> Message>>;
+type Decoder = Box<dyn FnMut(&Message) -> bool>;
 
+#[derive(Clone)]
 pub enum Message {
     Text(String),
     Binary(Box<[u8]>),
 }
+impl Message {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Message::Text(message) => message.is_empty(),
+            Message::Binary(message) => message.is_empty(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Message::Text(message) => message.as_bytes(),
+            Message::Binary(message) => message,
+        }
+    }
+
+    pub fn truncate(&self, max: usize) -> Message {
+        match self {
+            Message::Text(message) => {
+                let mut end = max.min(message.len());
+                while !message.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Message::Text(message[..end].to_owned())
+            }
+            Message::Binary(message) => Message::Binary(message[..max.min(message.len())].into()),
+        }
+    }
+
+    pub fn eq_content(&self, other: &Message) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+
+    pub fn text_ref(&self) -> Option<&str> {
+        match self {
+            Message::Text(message) => Some(message),
+            Message::Binary(_) => None,
+        }
+    }
+
+    pub fn binary_ref(&self) -> Option<&[u8]> {
+        match self {
+            Message::Text(_) => None,
+            Message::Binary(message) => Some(message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadyState {
+    Connecting,
+    Open,
+    Closing,
+    Closed,
+}
+
+#[derive(Debug, Clone)]
+pub enum WsError {
+    Connect(JsValue),
+    MixedContent,
+    DuplicateConnection,
+    Send(JsValue),
+    Socket(JsValue),
+    Close(JsValue),
+    UncleanClose { code: u16, reason: String },
+    Closed,
+    QueueFull,
+    ReconnectFailed { attempts: u32 },
+    HeartbeatTimeout,
+    SendOnly,
+    ReceiveOnly,
+    RequestTimeout,
+}
+impl WsError {
+    pub fn js_value(&self) -> Option<&JsValue> {
+        match self {
+            WsError::Connect(value)
+            | WsError::Send(value)
+            | WsError::Socket(value)
+            | WsError::Close(value) => Some(value),
+            WsError::MixedContent
+            | WsError::DuplicateConnection
+            | WsError::UncleanClose { .. }
+            | WsError::Closed
+            | WsError::QueueFull
+            | WsError::ReconnectFailed { .. }
+            | WsError::HeartbeatTimeout
+            | WsError::SendOnly
+            | WsError::ReceiveOnly
+            | WsError::RequestTimeout => None,
+        }
+    }
+}
+impl Display for WsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WsError::Connect(value) => write!(f, "could not create websocket: {value:?}"),
+            WsError::MixedContent => write!(
+                f,
+                "insecure ws:// connections are blocked on secure pages, use wss:// instead"
+            ),
+            WsError::DuplicateConnection => write!(f, "already connected to this url"),
+            WsError::Send(value) => write!(f, "could not send message: {value:?}"),
+            WsError::Socket(value) => write!(f, "websocket error: {value:?}"),
+            WsError::Close(value) => write!(f, "could not close websocket: {value:?}"),
+            WsError::UncleanClose { code, reason } => {
+                write!(f, "websocket closed uncleanly with code {code}: {reason}")
+            }
+            WsError::Closed => write!(f, "websocket is closed"),
+            WsError::QueueFull => write!(f, "message queue is full"),
+            WsError::ReconnectFailed { attempts } => {
+                write!(f, "gave up reconnecting after {attempts} attempts")
+            }
+            WsError::HeartbeatTimeout => write!(f, "no reply to heartbeat, connection is dead"),
+            WsError::SendOnly => write!(f, "websocket is send-only"),
+            WsError::ReceiveOnly => write!(f, "websocket is receive-only"),
+            WsError::RequestTimeout => write!(f, "no reply before the request timed out"),
+        }
+    }
+}
+impl Error for WsError {}
+
+#[derive(Debug, Clone)]
+pub struct WsSnapshot {
+    pub ready_state: ReadyState,
+    pub queued_len: usize,
+    pub queued_bytes: usize,
+    pub buffered_amount: u32,
+    pub delivered: usize,
+    pub messages_sent: usize,
+    pub messages_received: usize,
+    pub reconnect_attempts: u32,
+    pub uptime: Option<Duration>,
+    pub last_close: Option<CloseReason>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTiming {
+    pub dns_to_open: Option<Duration>,
+    pub open_to_first_message: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Coalesce {
+    #[default]
+    Both,
+    CloseOnly,
+    ErrorOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    #[default]
+    DropOldest,
+    DropNewest,
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectConfig {
+    pub max_retries: u32,
+    pub base_delay_ms: u32,
+    pub max_delay_ms: u32,
+    pub jitter: bool,
+}
+impl ReconnectConfig {
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay_ms);
+        if self.jitter {
+            // randomize over the upper half so clients dropped together don't retry together
+            Duration::from_secs_f64(f64::from(delay) * (0.5 + Math::random() / 2.0) / 1000.0)
+        } else {
+            Duration::from_millis(delay.into())
+        }
+    }
+}
+
+pub struct HeartbeatConfig {
+    pub interval_ms: u32,
+    pub timeout_ms: u32,
+    pub ping: Message,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    #[default]
+    Both,
+    SendOnly,
+    ReceiveOnly,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReason {
+    pub code: u16,
+    pub reason: String,
+    pub was_clean: bool,
+}
+impl From<&CloseEvent> for CloseReason {
+    fn from(event: &CloseEvent) -> Self {
+        Self {
+            code: event.code(),
+            reason: event.reason(),
+            was_clean: event.was_clean(),
+        }
+    }
+}
 
 struct HandlerCell<T> {
     function: RefCell<Handler<T>>,
@@ -43,6 +265,22 @@ impl<T> HandlerCell<T> {
             replacement: &self.replacement,
         }
     }
+    fn try_borrow_mut(&'_ self) -> Option<HandlerRef<'_, T>> {
+        Some(HandlerRef {
+            function: self.function.try_borrow_mut().ok()?,
+            replacement: &self.replacement,
+        })
+    }
+    fn take(&self) -> Option<Handler<T>> {
+        Some(self.function.try_borrow_mut().ok()?.take())
+    }
+    fn call_or_store(&self, store: &RefCell<Option<T>>, value: T) {
+        if let Some(ref mut handler) = *self.borrow_mut() {
+            handler(value);
+        } else {
+            *store.borrow_mut() = Some(value);
+        }
+    }
     fn replace(&self, new_handler: Option<Box<dyn FnMut(T)>>) -> bool {
         match self.function.try_borrow_mut() {
             Ok(mut old_handler) => {
@@ -76,189 +314,1788 @@ impl<T> Drop for HandlerRef<'_, T> {
     }
 }
 
-pub struct WebSocketClient {
-    raw_ws: WebSocket,
-    _raw_on_open: Option<EventListener>,
-    _raw_on_message: EventListener,
-    _raw_on_error: EventListener,
-    _raw_on_close: EventListener,
-    queue: Rc<RefCell<VecDeque<Message>>>,
-    error: Rc<RefCell<Option<JsValue>>>,
-    on_message: Rc<HandlerCell<Message>>,
-    on_error: Rc<HandlerCell<JsValue>>,
+thread_local! {
+    static ACTIVE_URLS: RefCell<HashMap<String, usize>> = RefCell::new(HashMap::new());
 }
-impl WebSocketClient {
-    pub fn new(url: &str, init_message: Option<Message>) -> Result<Self, JsValue> {
-        let queue = Rc::new(RefCell::new(VecDeque::new()));
-        let error = Rc::new(RefCell::new(None));
 
-        let on_message = Rc::new(HandlerCell::new());
-        let on_error = Rc::new(HandlerCell::new());
+#[cfg(debug_assertions)]
+thread_local! {
+    static LIVE_LISTENERS: Cell<usize> = const { Cell::new(0) };
+}
 
-        let raw_ws = WebSocket::new(url)?;
-        raw_ws.set_binary_type(BinaryType::Arraybuffer);
+#[cfg(debug_assertions)]
+pub fn live_listener_count() -> usize {
+    LIVE_LISTENERS.get()
+}
 
-        Ok(Self {
-            raw_ws: raw_ws.clone(),
-            _raw_on_open: init_message.map(|message| {
-                EventListener::new(raw_ws.clone().into(), "open", {
-                    let on_open_raw_ws = raw_ws.clone();
-                    let on_open_error = error.clone();
-                    let handler = on_error.clone();
-                    move |_| {
-                        let mut handler = handler.borrow_mut();
-                        let send_attempt = match &message {
-                            Message::Text(message) => on_open_raw_ws.send_with_str(message),
-                            Message::Binary(message) => on_open_raw_ws.send_with_u8_array(message),
-                        };
-                        if let Err(err) = send_attempt {
-                            if let Some(ref mut handler) = *handler {
-                                handler(err);
-                            } else {
-                                on_open_error.borrow_mut().replace(err);
-                            }
-                        }
-                    }
-                })
-            }),
-            _raw_on_message: EventListener::new(raw_ws.clone().into(), "message", {
-                let on_message_queue = queue.clone();
-                let handler = on_message.clone();
-                move |msg| {
-                    let msg = msg
-                        .dyn_into::<MessageEvent>()
-                        .expect("parameter of websocket message callback");
-                    let mut handler = handler.borrow_mut();
-                    let msg = if let Ok(msg) = msg.data().dyn_into::<ArrayBuffer>() {
-                        let array = Uint8Array::new(&msg);
-                        Message::Binary(array.to_vec().into_boxed_slice())
-                    } else if let Ok(msg) = msg.data().dyn_into::<JsString>() {
-                        Message::Text(msg.into())
-                    } else {
-                        // bail - not recognized binary or text message
-                        return;
-                    };
-                    if let Some(ref mut handler) = *handler {
-                        handler(msg);
-                    } else {
-                        on_message_queue.borrow_mut().push_back(msg);
-                    }
-                }
-            }),
-            _raw_on_error: EventListener::new(raw_ws.clone().into(), "error", {
-                let on_error_cell = error.clone();
-                let handler = on_error.clone();
-                move |error| {
-                    let mut handler = handler.borrow_mut();
-                    if let Some(ref mut handler) = *handler {
-                        handler(error.into());
-                    } else {
-                        *on_error_cell.borrow_mut() = Some(error.into());
-                    }
+struct State {
+    raw_ws: RefCell<WebSocket>,
+    url: String,
+    protocols: Vec<String>,
+    init_messages: Vec<Message>,
+    listeners: RefCell<Option<Listeners>>,
+    queue: RefCell<VecDeque<Message>>,
+    queue_limit: Cell<Option<usize>>,
+    drop_policy: Cell<DropPolicy>,
+    direction: Cell<Direction>,
+    error: RefCell<Option<WsError>>,
+    on_message: HandlerCell<Message>,
+    on_error: HandlerCell<WsError>,
+    on_open: HandlerCell<()>,
+    on_first_open: RefCell<Option<Box<dyn FnOnce()>>>,
+    has_opened: Cell<bool>,
+    closed: RefCell<Option<CloseReason>>,
+    last_close: RefCell<Option<CloseReason>>,
+    on_close: HandlerCell<CloseReason>,
+    on_message_raw: HandlerCell<MessageEvent>,
+    on_raw_data: RefCell<RawDataHandler>,
+    ignore_empty: Cell<bool>,
+    retain_last: Cell<bool>,
+    last_message: RefCell<Option<Message>>,
+    binary_as_text_if_utf8: Cell<bool>,
+    delivered: Cell<usize>,
+    messages_sent: Cell<usize>,
+    messages_received: Cell<usize>,
+    opened_at: Cell<Option<f64>>,
+    connecting_at: Cell<f64>,
+    first_opened_at: Cell<Option<f64>>,
+    first_message_at: Cell<Option<f64>>,
+    is_clean_close: RefCell<ClosePredicate>,
+    coalescing: Cell<Coalesce>,
+    errored: Cell<bool>,
+    coalesce_window: Cell<Option<Duration>>,
+    coalesce_timer: Cell<Option<i32>>,
+    coalesced: RefCell<Vec<Message>>,
+    on_batch: HandlerCell<Vec<Message>>,
+    coalesce_flush: Closure<dyn FnMut()>,
+    pending_replies: RefCell<Vec<PendingReply>>,
+    decoders: RefCell<Vec<Decoder>>,
+    streams: RefCell<Vec<Weak<StreamState>>>,
+    open_waiters: RefCell<Vec<Waker>>,
+    active_stream: RefCell<Weak<StreamState>>,
+    stashed_on_message: RefCell<Handler<Message>>,
+    reconnect: Cell<Option<ReconnectConfig>>,
+    reconnect_attempts: Cell<u32>,
+    reconnect_timer: Cell<Option<i32>>,
+    reconnect_callback: Closure<dyn FnMut()>,
+    close_requested: Cell<bool>,
+    resume_token: RefCell<Option<String>>,
+    resume_token_extractor: RefCell<ResumeTokenExtractor>,
+    resume_message: RefCell<ResumeMessage>,
+    is_resume_ack: RefCell<MessagePredicate>,
+    resume_acknowledged: Cell<Option<bool>>,
+    heartbeat: RefCell<Option<Heartbeat>>,
+    heartbeat_timer: Cell<Option<i32>>,
+    #[cfg(feature = "diagnostics")]
+    buffered_history: RefCell<VecDeque<(f64, u32)>>,
+}
+impl State {
+    fn raw_ws(&self) -> WebSocket {
+        self.raw_ws.borrow().clone()
+    }
+
+    fn count_sent(&self, sent: Result<(), JsValue>) -> Result<(), JsValue> {
+        if sent.is_ok() {
+            self.messages_sent.set(self.messages_sent.get() + 1);
+        }
+        sent
+    }
+
+    fn report_error(&self, err: WsError) {
+        self.on_error.call_or_store(&self.error, err);
+    }
+
+    fn handle_open(&self, _event: Event) {
+        let opened_at = now();
+        self.opened_at.set(Some(opened_at));
+        self.first_opened_at.set(Some(opened_at));
+        self.reconnect_attempts.set(0);
+        let raw_ws = self.raw_ws();
+        if let Err(err) = self
+            .init_messages
+            .iter()
+            .try_for_each(|message| self.count_sent(send_raw(&raw_ws, message)))
+            .and_then(|()| self.send_resume(&raw_ws))
+        {
+            self.report_error(WsError::Send(err));
+        }
+        if let Some(ref mut open_handler) = *self.on_open.borrow_mut() {
+            open_handler(());
+        }
+        self.has_opened.set(true);
+        let first_open = self.on_first_open.borrow_mut().take();
+        if let Some(handler) = first_open {
+            handler();
+        }
+    }
+
+    fn send_resume(&self, raw_ws: &WebSocket) -> Result<(), JsValue> {
+        self.resume_acknowledged.set(None);
+        // only opens after the first are reconnections worth resuming
+        if !self.has_opened.get() {
+            return Ok(());
+        }
+        let Some(ref token) = *self.resume_token.borrow() else {
+            return Ok(());
+        };
+        let Some(ref mut resume_message) = *self.resume_message.borrow_mut() else {
+            return Ok(());
+        };
+        self.count_sent(send_raw(raw_ws, &resume_message(token)))?;
+        self.resume_acknowledged.set(Some(false));
+        Ok(())
+    }
+
+    fn handle_message(&self, msg: Event) {
+        self.messages_received.set(self.messages_received.get() + 1);
+        if self.first_message_at.get().is_none() {
+            self.first_message_at.set(Some(now()));
+        }
+        if let Some(timer) = self.heartbeat_timer.take() {
+            clear_timeout(timer);
+        }
+        let msg = msg
+            .dyn_into::<MessageEvent>()
+            .expect("parameter of websocket message callback");
+        if let Some(ref mut on_raw_data) = *self.on_raw_data.borrow_mut() {
+            on_raw_data(&msg.data());
+        }
+        if let Some(ref mut raw_handler) = *self.on_message_raw.borrow_mut() {
+            raw_handler(msg);
+            return;
+        }
+        let mut handler = self.on_message.borrow_mut();
+        let msg = if let Ok(msg) = msg.data().dyn_into::<ArrayBuffer>() {
+            let array = Uint8Array::new(&msg).to_vec();
+            if self.binary_as_text_if_utf8.get() {
+                match String::from_utf8(array) {
+                    Ok(text) => Message::Text(text),
+                    Err(err) => Message::Binary(err.into_bytes().into_boxed_slice()),
                 }
-            }),
-            _raw_on_close: EventListener::new(raw_ws.clone().into(), "close", {
-                let on_close_cell = error.clone();
-                let error_handler = on_error.clone();
-                let on_message_queue = queue.clone();
-                let message_handler = on_message.clone();
-                move |event| {
-                    let close_event = event.dyn_into::<CloseEvent>();
-                    match close_event {
-                        Ok(event) if event.was_clean() => {
-                            let mut handler = message_handler.borrow_mut();
-                            if let Some(ref mut handler) = *handler {
-                                handler(Message::Text(event.reason()));
-                            } else {
-                                on_message_queue.borrow_mut().push_back(Message::Text(event.reason()));
-                            }
-                        }
-                        Ok(event) => {
-                            let mut handler = error_handler.borrow_mut();
-                            if let Some(ref mut handler) = *handler {
-                                handler(event.into());
-                            } else {
-                                *on_close_cell.borrow_mut() = Some(event.into())
-                            }
-                        }
-                        Err(event) => {
-                            let mut handler = error_handler.borrow_mut();
-                            if let Some(ref mut handler) = *handler {
-                                handler(event.into());
-                            } else {
-                                *on_close_cell.borrow_mut() = Some(event.into());
-                            }
-                        }
+            } else {
+                Message::Binary(array.into_boxed_slice())
+            }
+        } else if let Ok(msg) = msg.data().dyn_into::<JsString>() {
+            Message::Text(msg.into())
+        } else {
+            // bail - not recognized binary or text message
+            return;
+        };
+        if self.ignore_empty.get() && msg.is_empty() {
+            return;
+        }
+        if self.retain_last.get() {
+            *self.last_message.borrow_mut() = Some(msg.clone());
+        }
+        if let Some(ref mut extract) = *self.resume_token_extractor.borrow_mut()
+            && let Some(token) = extract(&msg)
+        {
+            *self.resume_token.borrow_mut() = Some(token);
+        }
+        if self.resume_acknowledged.get() == Some(false)
+            && let Some(ref mut is_ack) = *self.is_resume_ack.borrow_mut()
+            && is_ack(&msg)
+        {
+            self.resume_acknowledged.set(Some(true));
+        }
+        let msg = {
+            let mut pending_replies = self.pending_replies.borrow_mut();
+            pending_replies.retain(|pending| pending.slot.strong_count() > 0);
+            match pending_replies
+                .iter_mut()
+                .position(|pending| (pending.matches)(&msg))
+            {
+                Some(index) => {
+                    if let Some(slot) = pending_replies.remove(index).slot.upgrade() {
+                        slot.fill(Ok(msg));
                     }
+                    return;
                 }
-            }),
-            queue,
-            error,
-            on_message,
-            on_error,
-        })
+                None => msg,
+            }
+        };
+        if self
+            .decoders
+            .borrow_mut()
+            .iter_mut()
+            .any(|decoder| decoder(&msg))
+        {
+            return;
+        }
+        if let Some(window) = self.coalesce_window.get() {
+            self.coalesced.borrow_mut().push(msg);
+            if self.coalesce_timer.get().is_none() {
+                self.coalesce_timer.set(Some(set_timeout(
+                    self.coalesce_flush.as_ref().unchecked_ref(),
+                    window,
+                )));
+            }
+            return;
+        }
+        if let Some(ref mut handler) = *handler {
+            self.delivered.set(self.delivered.get() + 1);
+            handler(msg);
+        } else if !push_bounded(
+            &self.queue,
+            self.queue_limit.get(),
+            self.drop_policy.get(),
+            msg,
+        ) {
+            self.report_error(WsError::QueueFull);
+        }
+    }
+
+    fn handle_error(&self, error: Event) {
+        self.socket_error(WsError::Socket(error.into()));
     }
 
-    pub fn send(&mut self, message: &str) {
-        if let Err(err) = self.raw_ws.send_with_str(message) {
-            self.report_error(err);
+    fn socket_error(&self, err: WsError) {
+        self.errored.set(true);
+        if self.coalescing.get() == Coalesce::CloseOnly {
+            return;
         }
+        self.report_error(err);
     }
 
-    pub fn set_onmessage(&mut self, new_handler: Option<Box<dyn FnMut(Message)>>) {
-        if self.on_message.replace(new_handler) {
-            while let Some(ref mut handler) = *self.on_message.borrow_mut()
-                && let Some(message) = self.queue.borrow_mut().pop_front()
-            {
-                handler(message);
+    fn handle_close(&self, event: Event) {
+        let mut reason = match event.dyn_into::<CloseEvent>() {
+            Ok(event) => CloseReason::from(&event),
+            Err(event) => {
+                self.report_error(WsError::Socket(event.into()));
+                return;
             }
+        };
+        if let Some(ref mut is_clean) = *self.is_clean_close.borrow_mut() {
+            reason.was_clean = is_clean(&reason);
         }
+        self.socket_closed(reason);
     }
 
-    pub fn set_onerror(&mut self, new_handler: Option<Box<dyn FnMut(JsValue)>>) {
-        self.on_error.replace(new_handler);
-        if let Some(ref mut handler) = *self.on_error.borrow_mut()
-            && let Some(error) = self.error.borrow_mut().take()
-        {
-            handler(error);
+    fn socket_closed(&self, reason: CloseReason) {
+        self.opened_at.set(None);
+        if let Some(timer) = self.heartbeat_timer.take() {
+            clear_timeout(timer);
+        }
+        // replies to requests sent on this socket can't arrive on a reconnected one
+        for pending in self.pending_replies.take() {
+            if let Some(slot) = pending.slot.upgrade() {
+                slot.fill(Err(WsError::Closed));
+            }
+        }
+        let was_clean = reason.was_clean;
+        *self.last_close.borrow_mut() = Some(reason.clone());
+        let coalescing = self.coalescing.get();
+        let errored = self.errored.replace(false);
+        if !was_clean && coalescing != Coalesce::CloseOnly {
+            self.report_error(WsError::UncleanClose {
+                code: reason.code,
+                reason: reason.reason.clone(),
+            });
+        }
+        if !(errored && coalescing == Coalesce::ErrorOnly) {
+            self.on_close.call_or_store(&self.closed, reason);
+        }
+        if was_clean || self.close_requested.get() || !self.schedule_reconnect() {
+            self.end_streams();
         }
     }
 
-    fn report_error(&mut self, err: JsValue) {
-        if let Some(ref mut handler) = *self.on_error.borrow_mut() {
-            handler(err);
+    fn flush_coalesced(&self) {
+        self.coalesce_timer.set(None);
+        if self.coalesced.borrow().is_empty() {
+            return;
+        }
+        let (Some(mut batch_handler), Some(mut message_handler)) = (
+            self.on_batch.try_borrow_mut(),
+            self.on_message.try_borrow_mut(),
+        ) else {
+            // called from inside a handler, so keep the batch and retry once it returns
+            self.coalesce_timer.set(Some(set_timeout(
+                self.coalesce_flush.as_ref().unchecked_ref(),
+                Duration::ZERO,
+            )));
+            return;
+        };
+        let batch = self.coalesced.take();
+        if let Some(ref mut handler) = *batch_handler {
+            self.delivered.set(self.delivered.get() + batch.len());
+            handler(batch);
+        } else if let Some(ref mut handler) = *message_handler {
+            self.delivered.set(self.delivered.get() + batch.len());
+            batch.into_iter().for_each(handler);
         } else {
-            self.error.borrow_mut().replace(err);
+            for message in batch {
+                if !push_bounded(
+                    &self.queue,
+                    self.queue_limit.get(),
+                    self.drop_policy.get(),
+                    message,
+                ) {
+                    self.report_error(WsError::QueueFull);
+                }
+            }
+        }
+    }
+
+    fn heartbeat_expired(&self) {
+        self.heartbeat_timer.set(None);
+        // stop listening first so the socket's own close event isn't handled a second time
+        drop(self.listeners.take());
+        self.close_quietly();
+        self.socket_error(WsError::HeartbeatTimeout);
+        self.socket_closed(CloseReason {
+            code: ABNORMAL_CLOSURE,
+            reason: String::new(),
+            was_clean: false,
+        });
+    }
+
+    fn take_backlog(&self) -> VecDeque<Message> {
+        if let Some(timer) = self.coalesce_timer.take() {
+            clear_timeout(timer);
+        }
+        // anything still coalescing arrived after everything already queued
+        let mut backlog = self.queue.take();
+        backlog.extend(self.coalesced.take());
+        backlog
+    }
+
+    fn schedule_reconnect(&self) -> bool {
+        let Some(config) = self.reconnect.get() else {
+            return false;
+        };
+        let attempts = self.reconnect_attempts.get();
+        if attempts >= config.max_retries {
+            self.report_error(WsError::ReconnectFailed { attempts });
+            return false;
+        }
+        self.reconnect_attempts.set(attempts + 1);
+        self.reconnect_timer.set(Some(set_timeout(
+            self.reconnect_callback.as_ref().unchecked_ref(),
+            config.delay(attempts),
+        )));
+        true
+    }
+
+    fn reconnect(self: &Rc<Self>) {
+        self.reconnect_timer.set(None);
+        if self.close_requested.get() {
+            self.wake_open_waiters();
+            return;
+        }
+        // the old listeners are never running here, since this is only called from a timer
+        match open_socket(&self.url, &self.protocols) {
+            Ok(raw_ws) => {
+                *self.raw_ws.borrow_mut() = raw_ws;
+                self.connecting_at.set(now());
+                self.first_opened_at.set(None);
+                self.first_message_at.set(None);
+                let listeners = Listeners::attach(self);
+                *self.listeners.borrow_mut() = Some(listeners);
+            }
+            Err(err) => {
+                self.report_error(err);
+                self.end_streams();
+            }
+        }
+        self.wake_open_waiters();
+    }
+
+    fn sendable_socket(&self) -> Result<Option<WebSocket>, WsError> {
+        if self.direction.get() == Direction::ReceiveOnly {
+            return Err(WsError::ReceiveOnly);
+        }
+        let raw_ws = self.raw_ws();
+        // sending on a closing or closed socket is a no-op, so this is safe to call during teardown
+        if matches!(raw_ws.ready_state(), WebSocket::CLOSING | WebSocket::CLOSED) {
+            return Ok(None);
+        }
+        Ok(Some(raw_ws))
+    }
+
+    fn close_quietly(&self) {
+        // close without a code or reason can't throw
+        let _ = self.raw_ws().close();
+    }
+
+    fn request_close(&self) {
+        self.close_requested.set(true);
+        self.cancel_reconnect();
+    }
+
+    fn cancel_reconnect(&self) {
+        if let Some(timer) = self.reconnect_timer.take() {
+            clear_timeout(timer);
+            // the socket already closed, so there's no close event left to end these
+            self.end_streams();
+            self.wake_open_waiters();
+        }
+    }
+
+    fn wake_open_waiters(&self) {
+        for waker in self.open_waiters.take() {
+            waker.wake();
+        }
+    }
+
+    fn end_streams(&self) {
+        for stream in self.streams.take() {
+            if let Some(stream) = stream.upgrade() {
+                stream.closed.set(true);
+                stream.wake();
+            }
+        }
+    }
+}
+impl Drop for State {
+    fn drop(&mut self) {
+        if let Some(timer) = self.coalesce_timer.take() {
+            clear_timeout(timer);
+        }
+        if let Some(timer) = self.reconnect_timer.take() {
+            clear_timeout(timer);
+        }
+        if let Some(timer) = self.heartbeat_timer.take() {
+            clear_timeout(timer);
         }
     }
 }
 
-struct EventListener {
-    target: web_sys::EventTarget,
-    name: &'static str,
-    callback: Closure<dyn FnMut(Event)>,
+struct Heartbeat {
+    _interval: Interval,
+    expired: Closure<dyn FnMut()>,
 }
-impl EventListener {
-    fn new<F>(target: web_sys::EventTarget, name: &'static str, callback: F) -> Self
-    where
-        F: FnMut(Event) + 'static,
-    {
-        let callback = Closure::wrap(Box::new(callback) as Box<dyn FnMut(Event)>);
-        target
-            .add_event_listener_with_callback(name, callback.as_ref().unchecked_ref())
-            .unwrap();
 
+struct Listeners {
+    _open: EventListener,
+    message: Option<EventListener>,
+    _error: EventListener,
+    _close: EventListener,
+}
+impl Listeners {
+    fn attach(state: &Rc<State>) -> Self {
         Self {
-            target,
-            name,
-            callback,
+            _open: Self::listener(state, "open", State::handle_open),
+            message: Self::message_listener(state),
+            _error: Self::listener(state, "error", State::handle_error),
+            _close: Self::listener(state, "close", State::handle_close),
         }
     }
+
+    fn message_listener(state: &Rc<State>) -> Option<EventListener> {
+        (state.direction.get() != Direction::SendOnly)
+            .then(|| Self::listener(state, "message", State::handle_message))
+    }
+
+    fn listener(state: &Rc<State>, name: &'static str, handle: fn(&State, Event)) -> EventListener {
+        let weak = Rc::downgrade(state);
+        EventListener::new(state.raw_ws().into(), name, move |event| {
+            if let Some(state) = weak.upgrade() {
+                handle(&state, event);
+            }
+        })
+    }
 }
-impl Drop for EventListener {
-    fn drop(&mut self) {
-        self.target
-            .remove_event_listener_with_callback(self.name, self.callback.as_ref().unchecked_ref())
-            .unwrap();
+
+pub struct WebSocketClient {
+    state: Rc<State>,
+    on_drop_with_pending: Handler<usize>,
+    stall_monitor: Option<Interval>,
+    #[cfg(feature = "diagnostics")]
+    buffered_sampler: Option<Interval>,
+}
+impl WebSocketClient {
+    pub fn new(url: &str, init_messages: Vec<Message>) -> Result<Self, WsError> {
+        Self::new_with_protocols(url, &[], init_messages)
+    }
+
+    pub fn new_exclusive(url: &str, init_messages: Vec<Message>) -> Result<Self, WsError> {
+        if ACTIVE_URLS.with_borrow(|active| active.contains_key(url)) {
+            return Err(WsError::DuplicateConnection);
+        }
+        Self::new(url, init_messages)
+    }
+
+    pub fn new_with_protocols(
+        url: &str,
+        protocols: &[&str],
+        init_messages: Vec<Message>,
+    ) -> Result<Self, WsError> {
+        let protocols: Vec<String> = protocols.iter().copied().map(str::to_owned).collect();
+        let raw_ws = open_socket(url, &protocols)?;
+
+        let state = Rc::new_cyclic(|state: &Weak<State>| State {
+            raw_ws: RefCell::new(raw_ws),
+            url: url.to_owned(),
+            protocols,
+            init_messages,
+            listeners: RefCell::new(None),
+            queue: RefCell::new(VecDeque::new()),
+            queue_limit: Cell::new(None),
+            drop_policy: Cell::new(DropPolicy::default()),
+            direction: Cell::new(Direction::default()),
+            error: RefCell::new(None),
+            on_message: HandlerCell::new(),
+            on_error: HandlerCell::new(),
+            on_open: HandlerCell::new(),
+            on_first_open: RefCell::new(None),
+            has_opened: Cell::new(false),
+            closed: RefCell::new(None),
+            last_close: RefCell::new(None),
+            on_close: HandlerCell::new(),
+            on_message_raw: HandlerCell::new(),
+            on_raw_data: RefCell::new(None),
+            ignore_empty: Cell::new(false),
+            retain_last: Cell::new(false),
+            last_message: RefCell::new(None),
+            binary_as_text_if_utf8: Cell::new(false),
+            delivered: Cell::new(0),
+            messages_sent: Cell::new(0),
+            messages_received: Cell::new(0),
+            opened_at: Cell::new(None),
+            connecting_at: Cell::new(now()),
+            first_opened_at: Cell::new(None),
+            first_message_at: Cell::new(None),
+            is_clean_close: RefCell::new(None),
+            coalescing: Cell::new(Coalesce::default()),
+            errored: Cell::new(false),
+            coalesce_window: Cell::new(None),
+            coalesce_timer: Cell::new(None),
+            coalesced: RefCell::new(Vec::new()),
+            on_batch: HandlerCell::new(),
+            coalesce_flush: timer_callback(state, |state| state.flush_coalesced()),
+            pending_replies: RefCell::new(Vec::new()),
+            decoders: RefCell::new(Vec::new()),
+            streams: RefCell::new(Vec::new()),
+            open_waiters: RefCell::new(Vec::new()),
+            active_stream: RefCell::new(Weak::new()),
+            stashed_on_message: RefCell::new(None),
+            reconnect: Cell::new(None),
+            reconnect_attempts: Cell::new(0),
+            reconnect_timer: Cell::new(None),
+            reconnect_callback: timer_callback(state, State::reconnect),
+            close_requested: Cell::new(false),
+            resume_token: RefCell::new(None),
+            resume_token_extractor: RefCell::new(None),
+            resume_message: RefCell::new(None),
+            is_resume_ack: RefCell::new(None),
+            resume_acknowledged: Cell::new(None),
+            heartbeat: RefCell::new(None),
+            heartbeat_timer: Cell::new(None),
+            #[cfg(feature = "diagnostics")]
+            buffered_history: RefCell::new(VecDeque::new()),
+        });
+
+        // socket events are only dispatched from the event loop, so registering every listener
+        // before returning guarantees none are missed - registration order doesn't matter
+        let listeners = Listeners::attach(&state);
+        *state.listeners.borrow_mut() = Some(listeners);
+        ACTIVE_URLS.with_borrow_mut(|active| *active.entry(url.to_owned()).or_default() += 1);
+
+        Ok(Self {
+            state,
+            on_drop_with_pending: None,
+            stall_monitor: None,
+            #[cfg(feature = "diagnostics")]
+            buffered_sampler: None,
+        })
+    }
+
+    pub fn ready_state(&self) -> ReadyState {
+        match self.state.raw_ws.borrow().ready_state() {
+            WebSocket::CONNECTING => ReadyState::Connecting,
+            WebSocket::OPEN => ReadyState::Open,
+            WebSocket::CLOSING => ReadyState::Closing,
+            _ => ReadyState::Closed,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.ready_state() == ReadyState::Open
+    }
+
+    pub fn protocol(&self) -> String {
+        self.state.raw_ws.borrow().protocol()
+    }
+
+    /// Failures thrown by the browser while sending are returned as `WsError::Send` and are not
+    /// passed to the error handler. Sending on a closing or closed socket does nothing.
+    pub fn send(&mut self, message: &str) -> Result<(), WsError> {
+        match self.state.sendable_socket()? {
+            Some(raw_ws) => self
+                .state
+                .count_sent(raw_ws.send_with_str(message))
+                .map_err(WsError::Send),
+            None => Ok(()),
+        }
+    }
+
+    /// See [`WebSocketClient::send`].
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), WsError> {
+        match self.state.sendable_socket()? {
+            Some(raw_ws) => self
+                .state
+                .count_sent(raw_ws.send_with_u8_array(data))
+                .map_err(WsError::Send),
+            None => Ok(()),
+        }
+    }
+
+    /// See [`WebSocketClient::send`].
+    pub fn send_message(&mut self, message: &Message) -> Result<(), WsError> {
+        match self.state.sendable_socket()? {
+            Some(raw_ws) => self
+                .state
+                .count_sent(send_raw(&raw_ws, message))
+                .map_err(WsError::Send),
+            None => Ok(()),
+        }
+    }
+
+    pub fn buffered_amount(&self) -> u32 {
+        self.state.raw_ws.borrow().buffered_amount()
+    }
+
+    pub fn send_when_drained(
+        &mut self,
+        message: Message,
+        high_water: u32,
+    ) -> impl Future<Output = Result<(), WsError>> + use<> {
+        let client = Rc::downgrade(&self.state);
+        async move {
+            loop {
+                let Some(state) = client.upgrade() else {
+                    return Err(WsError::Closed);
+                };
+                let Some(raw_ws) = state.sendable_socket()? else {
+                    return Ok(());
+                };
+                if raw_ws.buffered_amount() < high_water {
+                    return state
+                        .count_sent(send_raw(&raw_ws, &message))
+                        .map_err(WsError::Send);
+                }
+                // don't keep the client alive while waiting
+                drop(state);
+                Delay::new(DRAIN_POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    pub fn set_onmessage(&mut self, new_handler: Option<Box<dyn FnMut(Message)>>) {
+        if self.state.on_message.replace(new_handler) {
+            self.replay_queue();
+        }
+    }
+
+    /// Receive-only clients still send their init messages, resume messages and heartbeat pings,
+    /// since those keep the subscription alive rather than carrying application data.
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.state.direction.set(direction);
+        if direction == Direction::SendOnly {
+            // nothing will read these any more
+            *self.state.queue.borrow_mut() = VecDeque::new();
+        }
+        if let Some(ref mut listeners) = *self.state.listeners.borrow_mut() {
+            if direction == Direction::SendOnly {
+                listeners.message = None;
+            } else if listeners.message.is_none() {
+                listeners.message = Listeners::message_listener(&self.state);
+            }
+        }
+    }
+
+    pub fn set_reconnect(&mut self, reconnect: Option<ReconnectConfig>) {
+        self.state.reconnect.set(reconnect);
+        if reconnect.is_none() {
+            self.state.cancel_reconnect();
+        }
+    }
+
+    pub fn set_queue_limit(&mut self, limit: Option<usize>) {
+        self.state.queue_limit.set(limit);
+    }
+
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.state.drop_policy.set(policy);
+    }
+
+    pub fn queued_len(&self) -> usize {
+        self.state.queue.borrow().len()
+    }
+
+    pub fn drain_messages(&mut self) -> Vec<Message> {
+        // taken messages are gone from both the queue and the coalesce buffer, so nothing is
+        // delivered twice
+        self.state.take_backlog().into()
+    }
+
+    pub fn drain_async_to_completion<F, Fut>(
+        &mut self,
+        mut handler: F,
+    ) -> impl Future<Output = ()> + use<F, Fut>
+    where
+        F: FnMut(Message) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let state = Rc::downgrade(&self.state);
+        async move {
+            // pop one at a time so messages queued while a handler is pending are drained too
+            while let Some(message) = state.upgrade().and_then(|state| {
+                if state.queue.borrow().is_empty() {
+                    // pull in whatever is still waiting out a coalesce window
+                    let backlog = state.take_backlog();
+                    *state.queue.borrow_mut() = backlog;
+                }
+                let message = state.queue.borrow_mut().pop_front()?;
+                state.delivered.set(state.delivered.get() + 1);
+                Some(message)
+            }) {
+                handler(message).await;
+            }
+        }
+    }
+
+    pub fn take_error(&mut self) -> Option<WsError> {
+        self.state.error.borrow_mut().take()
+    }
+
+    pub fn set_queue(&mut self, messages: VecDeque<Message>) {
+        *self.state.queue.borrow_mut() = messages;
+        self.replay_queue();
+    }
+
+    pub fn set_onmessage_raw(&mut self, new_handler: Option<Box<dyn FnMut(MessageEvent)>>) {
+        self.state.on_message_raw.replace(new_handler);
+    }
+
+    pub fn set_on_raw_data(&mut self, new_handler: RawDataHandler) {
+        *self.state.on_raw_data.borrow_mut() = new_handler;
+    }
+
+    /// Receives errors that can't be returned from the call that caused them: socket errors,
+    /// unclean closes, full queues, failures sending the initial message or heartbeat pings, and
+    /// giving up on reconnecting. Errors raised while no handler is set are kept until one is.
+    pub fn set_onerror(&mut self, new_handler: Option<Box<dyn FnMut(WsError)>>) {
+        self.state.on_error.replace(new_handler);
+        if let Some(ref mut handler) = *self.state.on_error.borrow_mut()
+            && let Some(error) = self.state.error.borrow_mut().take()
+        {
+            handler(error);
+        }
+    }
+
+    pub fn delivered_count(&self) -> usize {
+        self.state.delivered.get()
+    }
+
+    pub fn connection_uptime(&self) -> Option<Duration> {
+        self.state
+            .opened_at
+            .get()
+            .map(|opened_at| elapsed(opened_at, now()))
+    }
+
+    pub fn handshake_timing(&self) -> HandshakeTiming {
+        let opened_at = self.state.first_opened_at.get();
+        HandshakeTiming {
+            dns_to_open: opened_at
+                .map(|opened_at| elapsed(self.state.connecting_at.get(), opened_at)),
+            open_to_first_message: opened_at
+                .zip(self.state.first_message_at.get())
+                .map(|(opened_at, first_message_at)| elapsed(opened_at, first_message_at)),
+        }
+    }
+
+    pub fn snapshot(&self) -> WsSnapshot {
+        WsSnapshot {
+            ready_state: self.ready_state(),
+            queued_len: self.queued_len(),
+            queued_bytes: self
+                .state
+                .queue
+                .borrow()
+                .iter()
+                .map(|message| message.as_bytes().len())
+                .sum(),
+            buffered_amount: self.buffered_amount(),
+            delivered: self.delivered_count(),
+            messages_sent: self.state.messages_sent.get(),
+            messages_received: self.state.messages_received.get(),
+            reconnect_attempts: self.state.reconnect_attempts.get(),
+            uptime: self.connection_uptime(),
+            last_close: self.state.last_close.borrow().clone(),
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.state.delivered.set(0);
+        self.state.messages_sent.set(0);
+        self.state.messages_received.set(0);
+        #[cfg(feature = "diagnostics")]
+        self.state.buffered_history.borrow_mut().clear();
+    }
+
+    pub fn set_onopen(&mut self, new_handler: Option<Box<dyn FnMut()>>) {
+        let new_handler =
+            new_handler.map(|mut handler| Box::new(move |()| handler()) as Box<dyn FnMut(())>);
+        if self.state.on_open.replace(new_handler)
+            && self.is_open()
+            && let Some(ref mut handler) = *self.state.on_open.borrow_mut()
+        {
+            handler(());
+        }
+    }
+
+    pub fn set_onclose(&mut self, new_handler: Option<Box<dyn FnMut(CloseReason)>>) {
+        if self.state.on_close.replace(new_handler)
+            && let Some(ref mut handler) = *self.state.on_close.borrow_mut()
+            && let Some(reason) = self.state.closed.borrow_mut().take()
+        {
+            handler(reason);
+        }
+    }
+
+    pub fn set_ignore_empty(&mut self, ignore_empty: bool) {
+        self.state.ignore_empty.set(ignore_empty);
+    }
+
+    pub fn set_retain_last(&mut self, retain_last: bool) {
+        self.state.retain_last.set(retain_last);
+        if !retain_last {
+            self.state.last_message.take();
+        }
+    }
+
+    pub fn replay_last(&mut self) {
+        let Some(message) = self.state.last_message.borrow().clone() else {
+            return;
+        };
+        if let Some(mut handler) = self.state.on_message.try_borrow_mut()
+            && let Some(ref mut handler) = *handler
+        {
+            self.state.delivered.set(self.state.delivered.get() + 1);
+            handler(message);
+        }
+    }
+
+    pub fn set_binary_as_text_if_utf8(&mut self, binary_as_text_if_utf8: bool) {
+        self.state
+            .binary_as_text_if_utf8
+            .set(binary_as_text_if_utf8);
+    }
+
+    pub fn set_is_clean_close(&mut self, is_clean_close: ClosePredicate) {
+        *self.state.is_clean_close.borrow_mut() = is_clean_close;
+    }
+
+    pub fn set_error_close_coalescing(&mut self, coalescing: Coalesce) {
+        self.state.coalescing.set(coalescing);
+    }
+
+    pub fn set_coalesce_window(&mut self, window: Option<Duration>) {
+        self.state.coalesce_window.set(window);
+    }
+
+    pub fn flush_coalesced(&mut self) {
+        if let Some(timer) = self.state.coalesce_timer.take() {
+            clear_timeout(timer);
+        }
+        self.state.flush_coalesced();
+    }
+
+    pub fn set_onmessage_batch(&mut self, new_handler: Option<Box<dyn FnMut(Vec<Message>)>>) {
+        self.state.on_batch.replace(new_handler);
+    }
+
+    pub fn set_on_stalled(&mut self, timeout: Duration, new_handler: Option<Box<dyn FnMut()>>) {
+        self.stall_monitor = new_handler.map(|mut handler| {
+            let state = Rc::downgrade(&self.state);
+            let mut last_amount = 0;
+            let mut stalled = false;
+            Interval::new(timeout, move || {
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+                let amount = state.raw_ws.borrow().buffered_amount();
+                if amount != 0 && amount == last_amount {
+                    if !stalled {
+                        stalled = true;
+                        handler();
+                    }
+                } else {
+                    stalled = false;
+                }
+                last_amount = amount;
+            })
+        });
+    }
+
+    #[cfg(feature = "diagnostics")]
+    pub fn sample_buffered_amount(&mut self, period: Option<Duration>, capacity: usize) {
+        self.buffered_sampler = period.map(|period| {
+            let state = Rc::downgrade(&self.state);
+            Interval::new(period, move || {
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+                let timestamp = now();
+                let amount = state.raw_ws.borrow().buffered_amount();
+                let mut history = state.buffered_history.borrow_mut();
+                while history.len() >= capacity.max(1) {
+                    history.pop_front();
+                }
+                history.push_back((timestamp, amount));
+            })
+        });
+    }
+
+    #[cfg(feature = "diagnostics")]
+    pub fn buffered_history(&self) -> Vec<(f64, u32)> {
+        self.state
+            .buffered_history
+            .borrow()
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    pub fn set_resume_token_extractor(&mut self, extractor: ResumeTokenExtractor) {
+        *self.state.resume_token_extractor.borrow_mut() = extractor;
+    }
+
+    pub fn set_resume_message(&mut self, resume_message: ResumeMessage) {
+        *self.state.resume_message.borrow_mut() = resume_message;
+    }
+
+    pub fn set_is_resume_ack(&mut self, is_resume_ack: MessagePredicate) {
+        *self.state.is_resume_ack.borrow_mut() = is_resume_ack;
+    }
+
+    pub fn resume_token(&self) -> Option<String> {
+        self.state.resume_token.borrow().clone()
+    }
+
+    /// `None` if the last open didn't try to resume, otherwise whether the server acknowledged it.
+    pub fn resume_acknowledged(&self) -> Option<bool> {
+        self.state.resume_acknowledged.get()
+    }
+
+    pub fn enable_heartbeat(&mut self, config: HeartbeatConfig) {
+        let HeartbeatConfig {
+            interval_ms,
+            timeout_ms,
+            ping,
+        } = config;
+        let state = Rc::downgrade(&self.state);
+        let interval = Interval::new(Duration::from_millis(interval_ms.into()), move || {
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let raw_ws = state.raw_ws();
+            // skip while connecting or reconnecting, or still waiting on a reply to the last ping
+            if raw_ws.ready_state() != WebSocket::OPEN || state.heartbeat_timer.get().is_some() {
+                return;
+            }
+            if let Err(err) = state.count_sent(send_raw(&raw_ws, &ping)) {
+                state.report_error(WsError::Send(err));
+                return;
+            }
+            // replies can't be seen without a message listener
+            if state.direction.get() == Direction::SendOnly {
+                return;
+            }
+            if let Some(ref heartbeat) = *state.heartbeat.borrow() {
+                state.heartbeat_timer.set(Some(set_timeout(
+                    heartbeat.expired.as_ref().unchecked_ref(),
+                    Duration::from_millis(timeout_ms.into()),
+                )));
+            }
+        });
+        self.disable_heartbeat();
+        *self.state.heartbeat.borrow_mut() = Some(Heartbeat {
+            _interval: interval,
+            expired: timer_callback(&Rc::downgrade(&self.state), |state| {
+                state.heartbeat_expired()
+            }),
+        });
+    }
+
+    pub fn disable_heartbeat(&mut self) {
+        if let Some(timer) = self.state.heartbeat_timer.take() {
+            clear_timeout(timer);
+        }
+        drop(self.state.heartbeat.take());
+    }
+
+    pub fn set_on_first_open(&mut self, new_handler: Option<Box<dyn FnOnce()>>) {
+        match new_handler {
+            Some(handler) if self.state.has_opened.get() => handler(),
+            new_handler => *self.state.on_first_open.borrow_mut() = new_handler,
+        }
+    }
+
+    pub fn set_on_drop_with_pending(&mut self, new_handler: Option<Box<dyn FnMut(usize)>>) {
+        self.on_drop_with_pending = new_handler;
+    }
+
+    pub fn message_stream(&mut self) -> MessageStream {
+        // the new stream takes over delivery, so earlier ones would otherwise wait forever
+        self.state.end_streams();
+        // listeners are already gone if a heartbeat declared the socket dead
+        let closed = self.state.reconnect_timer.get().is_none()
+            && (self.ready_state() == ReadyState::Closed
+                || self.state.listeners.borrow().is_none());
+        let state = Rc::new(StreamState {
+            buffer: RefCell::new(VecDeque::new()),
+            closed: Cell::new(closed),
+            waker: RefCell::new(None),
+        });
+        self.state.streams.borrow_mut().push(Rc::downgrade(&state));
+        self.set_onmessage(Some(Box::new({
+            let stream = Rc::downgrade(&state);
+            let client = Rc::downgrade(&self.state);
+            move |message| match stream.upgrade() {
+                Some(stream) => {
+                    stream.buffer.borrow_mut().push_back(message);
+                    stream.wake();
+                }
+                // queue for whoever takes over once the stream has been dropped
+                None => {
+                    if let Some(client) = client.upgrade()
+                        && !push_bounded(
+                            &client.queue,
+                            client.queue_limit.get(),
+                            client.drop_policy.get(),
+                            message,
+                        )
+                    {
+                        client.report_error(WsError::QueueFull);
+                    }
+                }
+            }
+        })));
+        *self.state.active_stream.borrow_mut() = Rc::downgrade(&state);
+        MessageStream { state }
+    }
+
+    pub fn take_stream(&mut self) -> Option<MessageStream> {
+        if self
+            .state
+            .active_stream
+            .borrow()
+            .upgrade()
+            .is_some_and(|stream| !stream.closed.get())
+        {
+            return None;
+        }
+        // can't swap out the handler from inside itself
+        let handler = self.state.on_message.take()?;
+        *self.state.stashed_on_message.borrow_mut() = handler;
+        Some(self.message_stream())
+    }
+
+    pub fn revert_to_callback(&mut self) {
+        let Some(stream) = self.state.active_stream.take().upgrade() else {
+            return;
+        };
+        stream.closed.set(true);
+        stream.wake();
+        {
+            // hand back whatever the stream hasn't yielded yet, ahead of anything queued since
+            let mut queue = self.state.queue.borrow_mut();
+            for message in stream.buffer.take().into_iter().rev() {
+                queue.push_front(message);
+            }
+        }
+        let handler = self.state.stashed_on_message.take();
+        self.set_onmessage(handler);
+    }
+
+    pub fn await_open(&self) -> OpenFuture {
+        OpenFuture {
+            state: Rc::downgrade(&self.state),
+            outcome: Rc::new(RefCell::new(None)),
+            waker: Rc::new(RefCell::new(None)),
+            listeners: None,
+        }
+    }
+
+    pub fn request<F>(&mut self, message: Message, match_reply: F) -> ReplyFuture
+    where
+        F: FnMut(&Message) -> bool + 'static,
+    {
+        let slot = Rc::new(Slot::new());
+        let sent = if self.state.direction.get() == Direction::SendOnly {
+            Err(WsError::SendOnly)
+        } else if self.state.direction.get() == Direction::ReceiveOnly {
+            Err(WsError::ReceiveOnly)
+        } else if self.is_closing_or_closed() || self.state.reconnect_timer.get().is_some() {
+            // the close already failed the pending requests, so nothing would ever fail this one
+            Err(WsError::Closed)
+        } else {
+            self.state
+                .count_sent(send_raw(&self.state.raw_ws(), &message))
+                .map_err(WsError::Send)
+        };
+        match sent {
+            Ok(()) => self.state.pending_replies.borrow_mut().push(PendingReply {
+                matches: Box::new(match_reply),
+                slot: Rc::downgrade(&slot),
+            }),
+            Err(err) => slot.fill(Err(err)),
+        }
+        ReplyFuture {
+            slot,
+            timeout: None,
+        }
+    }
+
+    pub fn request_with_timeout<F>(
+        &mut self,
+        message: Message,
+        timeout: Duration,
+        match_reply: F,
+    ) -> ReplyFuture
+    where
+        F: FnMut(&Message) -> bool + 'static,
+    {
+        ReplyFuture {
+            timeout: Some(Delay::new(timeout)),
+            ..self.request(message, match_reply)
+        }
+    }
+
+    pub fn add_decoder<T, D, H>(&mut self, mut decoder: D, mut handler: H)
+    where
+        D: FnMut(&Message) -> Option<T> + 'static,
+        H: FnMut(T) + 'static,
+    {
+        self.state
+            .decoders
+            .borrow_mut()
+            .push(Box::new(move |message| match decoder(message) {
+                Some(decoded) => {
+                    handler(decoded);
+                    true
+                }
+                None => false,
+            }));
+    }
+
+    /// Closing a socket that is already closing or closed does nothing.
+    pub fn close(&mut self) -> Result<(), WsError> {
+        self.state.request_close();
+        self.state.raw_ws().close().map_err(WsError::Close)
+    }
+
+    /// Only codes 1000 and 3000-4999 and reasons of at most 123 bytes are accepted.
+    /// Closing a socket that is already closing or closed does nothing.
+    pub fn close_with_code_and_reason(&mut self, code: u16, reason: &str) -> Result<(), WsError> {
+        self.state
+            .raw_ws()
+            .close_with_code_and_reason(code, reason)
+            .map_err(WsError::Close)?;
+        self.state.request_close();
+        Ok(())
+    }
+
+    pub fn drain_then_close<F>(&mut self, mut handler: F)
+    where
+        F: FnMut(Message),
+    {
+        // hand messages over directly so whatever handler or stream is installed stays in place
+        for message in self.state.take_backlog() {
+            self.state.delivered.set(self.state.delivered.get() + 1);
+            handler(message);
+        }
+        self.state.request_close();
+        self.state.close_quietly();
+    }
+
+    pub fn close_graceful(&mut self) -> CloseFuture {
+        self.state.request_close();
+        let slot = Rc::new(Slot::new());
+        if self.ready_state() == ReadyState::Closed {
+            slot.fill(None);
+            return CloseFuture {
+                slot,
+                _listener: None,
+            };
+        }
+        let listener = EventListener::new(self.state.raw_ws().into(), "close", {
+            let slot = slot.clone();
+            move |event| {
+                slot.fill(
+                    event
+                        .dyn_into::<CloseEvent>()
+                        .ok()
+                        .map(|event| CloseReason::from(&event)),
+                )
+            }
+        });
+        self.state.close_quietly();
+        CloseFuture {
+            slot,
+            _listener: Some(listener),
+        }
+    }
+
+    pub fn into_raw(self) -> WebSocket {
+        // dropping self removes our listeners but leaves the socket open
+        self.state.raw_ws()
+    }
+
+    pub fn run<M, E>(mut self, on_message: M, on_error: E) -> ClientGuard
+    where
+        M: FnMut(Message) + 'static,
+        E: FnMut(WsError) + 'static,
+    {
+        self.set_onmessage(Some(Box::new(on_message)));
+        self.set_onerror(Some(Box::new(on_error)));
+        ClientGuard(self)
+    }
+
+    fn is_closing_or_closed(&self) -> bool {
+        matches!(self.ready_state(), ReadyState::Closing | ReadyState::Closed)
+    }
+
+    fn replay_queue(&mut self) {
+        while let Some(mut handler) = self.state.on_message.try_borrow_mut()
+            && let Some(ref mut handler) = *handler
+            && let Some(message) = self.state.queue.borrow_mut().pop_front()
+        {
+            self.state.delivered.set(self.state.delivered.get() + 1);
+            handler(message);
+        }
+    }
+}
+
+impl Drop for WebSocketClient {
+    fn drop(&mut self) {
+        ACTIVE_URLS.with_borrow_mut(|active| {
+            if let Some(count) = active.get_mut(&self.state.url) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&self.state.url);
+                }
+            }
+        });
+        let pending = self.queued_len();
+        if pending > 0
+            && let Some(ref mut handler) = self.on_drop_with_pending
+        {
+            handler(pending);
+        }
+    }
+}
+
+#[must_use = "dropping the guard drops the client and its event listeners"]
+pub struct ClientGuard(WebSocketClient);
+impl Deref for ClientGuard {
+    type Target = WebSocketClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl DerefMut for ClientGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+struct StreamState {
+    buffer: RefCell<VecDeque<Message>>,
+    closed: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+impl StreamState {
+    fn wake(&self) {
+        if let Some(waker) = self.waker.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+}
+
+pub struct MessageStream {
+    state: Rc<StreamState>,
+}
+impl MessageStream {
+    pub async fn next(&mut self) -> Option<Message> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+impl Stream for MessageStream {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        if let Some(message) = self.state.buffer.borrow_mut().pop_front() {
+            return Poll::Ready(Some(message));
+        }
+        if self.state.closed.get() {
+            return Poll::Ready(None);
+        }
+        *self.state.waker.borrow_mut() = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+pub struct OpenFuture {
+    state: Weak<State>,
+    outcome: Rc<RefCell<Option<Result<(), WsError>>>>,
+    waker: Rc<RefCell<Option<Waker>>>,
+    listeners: Option<(WebSocket, Vec<EventListener>)>,
+}
+impl Future for OpenFuture {
+    type Output = Result<(), WsError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(state) = self.state.upgrade() else {
+            return Poll::Ready(Err(WsError::Closed));
+        };
+        let reconnecting = state.reconnect_timer.get().is_some();
+        if let Some(outcome) = self.outcome.borrow_mut().take()
+            && (outcome.is_ok() || !reconnecting)
+        {
+            return Poll::Ready(outcome);
+        }
+        let raw_ws = state.raw_ws();
+        match raw_ws.ready_state() {
+            WebSocket::CONNECTING => {}
+            WebSocket::OPEN => return Poll::Ready(Ok(())),
+            _ if reconnecting => {
+                // woken again once the replacement socket exists
+                state.open_waiters.borrow_mut().push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            _ => return Poll::Ready(Err(WsError::Closed)),
+        }
+
+        *self.waker.borrow_mut() = Some(cx.waker().clone());
+        if self
+            .listeners
+            .as_ref()
+            .is_none_or(|(watched, _)| *watched != raw_ws)
+        {
+            let listener = |name: &'static str, outcome_of: fn(Event) -> Result<(), WsError>| {
+                let outcome = self.outcome.clone();
+                let waker = self.waker.clone();
+                EventListener::new(raw_ws.clone().into(), name, move |event| {
+                    outcome
+                        .borrow_mut()
+                        .get_or_insert_with(|| outcome_of(event));
+                    if let Some(waker) = waker.borrow_mut().take() {
+                        waker.wake();
+                    }
+                })
+            };
+            let mut listeners = vec![
+                listener("open", |_| Ok(())),
+                listener("close", |event| match event.dyn_into::<CloseEvent>() {
+                    Ok(event) => Err(WsError::UncleanClose {
+                        code: event.code(),
+                        reason: event.reason(),
+                    }),
+                    Err(event) => Err(WsError::Socket(event.into())),
+                }),
+            ];
+            // an error is always followed by a close, which decides whether to reconnect
+            if state.reconnect.get().is_none() {
+                listeners.push(listener("error", |event| {
+                    Err(WsError::Socket(event.into()))
+                }));
+            }
+            self.listeners = Some((raw_ws, listeners));
+        }
+        Poll::Pending
+    }
+}
+
+struct Slot<T> {
+    value: RefCell<Option<T>>,
+    waker: RefCell<Option<Waker>>,
+}
+impl<T> Slot<T> {
+    fn new() -> Self {
+        Self {
+            value: RefCell::new(None),
+            waker: RefCell::new(None),
+        }
+    }
+    fn fill(&self, value: T) {
+        *self.value.borrow_mut() = Some(value);
+        if let Some(waker) = self.waker.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+    fn poll(&self, cx: &mut Context<'_>) -> Poll<T> {
+        match self.value.borrow_mut().take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                *self.waker.borrow_mut() = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct PendingReply {
+    matches: Box<dyn FnMut(&Message) -> bool>,
+    slot: Weak<Slot<Result<Message, WsError>>>,
+}
+
+pub struct ReplyFuture {
+    slot: Rc<Slot<Result<Message, WsError>>>,
+    timeout: Option<Delay>,
+}
+impl Future for ReplyFuture {
+    type Output = Result<Message, WsError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(reply) = self.slot.poll(cx) {
+            return Poll::Ready(reply);
+        }
+        if let Some(ref mut timeout) = self.timeout
+            && Pin::new(timeout).poll(cx).is_ready()
+        {
+            return Poll::Ready(Err(WsError::RequestTimeout));
+        }
+        Poll::Pending
+    }
+}
+
+const ABNORMAL_CLOSURE: u16 = 1006;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+struct Delay {
+    slot: Rc<Slot<()>>,
+    handle: i32,
+    _callback: Closure<dyn FnMut()>,
+}
+impl Delay {
+    fn new(timeout: Duration) -> Self {
+        let slot = Rc::new(Slot::new());
+        let callback = Closure::wrap(Box::new({
+            let slot = slot.clone();
+            move || slot.fill(())
+        }) as Box<dyn FnMut()>);
+        let handle = set_timeout(callback.as_ref().unchecked_ref(), timeout);
+        Self {
+            slot,
+            handle,
+            _callback: callback,
+        }
+    }
+}
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.slot.poll(cx)
+    }
+}
+impl Drop for Delay {
+    fn drop(&mut self) {
+        clear_timeout(self.handle);
+    }
+}
+
+pub struct CloseFuture {
+    slot: Rc<Slot<Option<CloseReason>>>,
+    _listener: Option<EventListener>,
+}
+impl Future for CloseFuture {
+    type Output = Option<CloseReason>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.slot.poll(cx)
+    }
+}
+
+fn push_bounded(
+    queue: &RefCell<VecDeque<Message>>,
+    limit: Option<usize>,
+    policy: DropPolicy,
+    message: Message,
+) -> bool {
+    let mut queue = queue.borrow_mut();
+    if let Some(limit) = limit
+        && queue.len() >= limit
+    {
+        match policy {
+            DropPolicy::DropOldest if limit > 0 => {
+                while queue.len() >= limit {
+                    queue.pop_front();
+                }
+            }
+            DropPolicy::DropOldest | DropPolicy::DropNewest => return true,
+            DropPolicy::Reject => return false,
+        }
+    }
+    queue.push_back(message);
+    true
+}
+
+fn open_socket(url: &str, protocols: &[String]) -> Result<WebSocket, WsError> {
+    let raw_ws = WebSocket::new_with_str_sequence(
+        url,
+        &protocols
+            .iter()
+            .map(|protocol| JsValue::from_str(protocol))
+            .collect::<Array>(),
+    )
+    .map_err(|err| {
+        let is_security_error = err
+            .dyn_ref::<DomException>()
+            .is_some_and(|err| err.name() == "SecurityError");
+        if is_security_error
+            && url
+                .get(..5)
+                .is_some_and(|scheme| scheme.eq_ignore_ascii_case("ws://"))
+        {
+            WsError::MixedContent
+        } else {
+            WsError::Connect(err)
+        }
+    })?;
+    raw_ws.set_binary_type(BinaryType::Arraybuffer);
+    Ok(raw_ws)
+}
+
+fn send_raw(raw_ws: &WebSocket, message: &Message) -> Result<(), JsValue> {
+    match message {
+        Message::Text(message) => raw_ws.send_with_str(message),
+        Message::Binary(message) => raw_ws.send_with_u8_array(message),
+    }
+}
+
+// timers and performance are shared by windows and workers, so bind them on whichever is global
+#[wasm_bindgen]
+extern "C" {
+    type GlobalScope;
+
+    #[wasm_bindgen(method, getter)]
+    fn performance(this: &GlobalScope) -> Option<Performance>;
+    #[wasm_bindgen(method, js_name = setTimeout)]
+    fn set_timeout(this: &GlobalScope, callback: &Function, timeout: i32) -> i32;
+    #[wasm_bindgen(method, js_name = clearTimeout)]
+    fn clear_timeout(this: &GlobalScope, handle: i32);
+    #[wasm_bindgen(method, js_name = setInterval)]
+    fn set_interval(this: &GlobalScope, callback: &Function, timeout: i32) -> i32;
+    #[wasm_bindgen(method, js_name = clearInterval)]
+    fn clear_interval(this: &GlobalScope, handle: i32);
+}
+
+fn global() -> GlobalScope {
+    js_sys::global().unchecked_into()
+}
+
+fn now() -> f64 {
+    global()
+        .performance()
+        .map_or(0.0, |performance| performance.now())
+}
+
+fn elapsed(from: f64, to: f64) -> Duration {
+    Duration::from_secs_f64((to - from).max(0.0) / 1000.0)
+}
+
+fn set_timeout(callback: &Function, timeout: Duration) -> i32 {
+    global().set_timeout(
+        callback,
+        i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX),
+    )
+}
+fn clear_timeout(handle: i32) {
+    global().clear_timeout(handle);
+}
+
+fn timer_callback(state: &Weak<State>, callback: fn(&Rc<State>)) -> Closure<dyn FnMut()> {
+    let state = state.clone();
+    Closure::wrap(Box::new(move || {
+        if let Some(state) = state.upgrade() {
+            callback(&state);
+        }
+    }) as Box<dyn FnMut()>)
+}
+
+struct Interval {
+    handle: i32,
+    _callback: Closure<dyn FnMut()>,
+}
+impl Interval {
+    fn new<F>(period: Duration, callback: F) -> Self
+    where
+        F: FnMut() + 'static,
+    {
+        let callback = Closure::wrap(Box::new(callback) as Box<dyn FnMut()>);
+        let handle = global().set_interval(
+            callback.as_ref().unchecked_ref(),
+            i32::try_from(period.as_millis()).unwrap_or(i32::MAX),
+        );
+
+        Self {
+            handle,
+            _callback: callback,
+        }
+    }
+}
+impl Drop for Interval {
+    fn drop(&mut self) {
+        global().clear_interval(self.handle);
+    }
+}
+
+struct EventListener {
+    target: web_sys::EventTarget,
+    name: &'static str,
+    callback: Closure<dyn FnMut(Event)>,
+}
+impl EventListener {
+    fn new<F>(target: web_sys::EventTarget, name: &'static str, callback: F) -> Self
+    where
+        F: FnMut(Event) + 'static,
+    {
+        let callback = Closure::wrap(Box::new(callback) as Box<dyn FnMut(Event)>);
+        target
+            .add_event_listener_with_callback(name, callback.as_ref().unchecked_ref())
+            .unwrap();
+        #[cfg(debug_assertions)]
+        LIVE_LISTENERS.set(LIVE_LISTENERS.get() + 1);
+
+        Self {
+            target,
+            name,
+            callback,
+        }
+    }
+}
+impl Drop for EventListener {
+    fn drop(&mut self) {
+        self.target
+            .remove_event_listener_with_callback(self.name, self.callback.as_ref().unchecked_ref())
+            .unwrap();
+        #[cfg(debug_assertions)]
+        LIVE_LISTENERS.set(LIVE_LISTENERS.get() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(message: &str) -> Message {
+        Message::Text(message.to_owned())
+    }
+
+    fn push_all(limit: Option<usize>, policy: DropPolicy) -> (Vec<bool>, Vec<String>) {
+        let queue = RefCell::new(VecDeque::new());
+        let accepted = ["a", "b", "c"]
+            .into_iter()
+            .map(|message| push_bounded(&queue, limit, policy, text(message)))
+            .collect();
+        let queued = queue
+            .into_inner()
+            .iter()
+            .map(|message| message.text_ref().unwrap().to_owned())
+            .collect();
+        (accepted, queued)
+    }
+
+    #[test]
+    fn push_bounded_unlimited() {
+        for policy in [
+            DropPolicy::DropOldest,
+            DropPolicy::DropNewest,
+            DropPolicy::Reject,
+        ] {
+            assert_eq!(
+                push_all(None, policy),
+                (vec![true; 3], vec!["a".into(), "b".into(), "c".into()])
+            );
+        }
+    }
+
+    #[test]
+    fn push_bounded_drop_oldest() {
+        assert_eq!(
+            push_all(Some(2), DropPolicy::DropOldest),
+            (vec![true; 3], vec!["b".into(), "c".into()])
+        );
+    }
+
+    #[test]
+    fn push_bounded_drop_newest() {
+        assert_eq!(
+            push_all(Some(2), DropPolicy::DropNewest),
+            (vec![true; 3], vec!["a".into(), "b".into()])
+        );
+    }
+
+    #[test]
+    fn push_bounded_reject() {
+        assert_eq!(
+            push_all(Some(2), DropPolicy::Reject),
+            (vec![true, true, false], vec!["a".into(), "b".into()])
+        );
+    }
+
+    #[test]
+    fn push_bounded_zero_limit() {
+        assert_eq!(
+            push_all(Some(0), DropPolicy::DropOldest),
+            (vec![true; 3], vec![])
+        );
+        assert_eq!(
+            push_all(Some(0), DropPolicy::DropNewest),
+            (vec![true; 3], vec![])
+        );
+        assert_eq!(
+            push_all(Some(0), DropPolicy::Reject),
+            (vec![false; 3], vec![])
+        );
+    }
+
+    #[test]
+    fn truncate_text_at_char_boundary() {
+        // 'é' is two bytes, so cutting at 2 would split it
+        let message = text("aéb");
+        assert_eq!(message.truncate(2).text_ref(), Some("a"));
+        assert_eq!(message.truncate(3).text_ref(), Some("aé"));
+        assert_eq!(message.truncate(10).text_ref(), Some("aéb"));
+        assert_eq!(message.truncate(0).text_ref(), Some(""));
+    }
+
+    #[test]
+    fn truncate_binary() {
+        let message = Message::Binary(Box::new([1, 2, 3]));
+        assert_eq!(message.truncate(2).binary_ref(), Some(&[1, 2][..]));
+        assert_eq!(message.truncate(5).binary_ref(), Some(&[1, 2, 3][..]));
+    }
+
+    #[test]
+    fn reconnect_delay_doubles_and_caps() {
+        let config = ReconnectConfig {
+            max_retries: 10,
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            jitter: false,
+        };
+        let delays: Vec<_> = (0..6).map(|attempt| config.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(config.delay(u32::MAX), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn eq_content_ignores_kind() {
+        let binary = Message::Binary(Box::from(&b"hello"[..]));
+        assert!(text("hello").eq_content(&binary));
+        assert!(binary.eq_content(&text("hello")));
+        assert!(!text("hello").eq_content(&text("world")));
     }
 }